@@ -4,42 +4,116 @@ use cpal::{
     traits::{DeviceTrait, HostTrait}
 };
 
-pub fn mk_stream(cur_weight: Arc<Mutex<f32>>) -> Result<cpal::Stream, cpal::BuildStreamError> {
+// Root of the overtone series each partial is built from.
+const A_110: f32 = 110.0;
+
+// Weight, in kg, at which the synth reaches full gain. Pulls beyond this just clip to
+// maximum loudness rather than going silent-to-deafening over an unbounded range.
+const MAX_WEIGHT_KG: f32 = 50.0;
+
+/// Tunable knobs for [`create_stream`]'s additive synthesis: how many harmonics to sum
+/// per voice, and the relative amplitude of each one (`k` is 1-indexed, `k == 1` being
+/// the fundamental).
+#[derive(Clone)]
+pub struct SynthParams {
+    pub partial_count: usize,
+    pub amplitude_curve: fn(usize) -> f32,
+}
+
+impl Default for SynthParams {
+    fn default() -> Self {
+        Self {
+            partial_count: 4,
+            amplitude_curve: |k| 1.0 / k as f32,
+        }
+    }
+}
+
+pub fn mk_stream(cur_weight: Arc<Mutex<f32>>, params: SynthParams) -> Result<cpal::Stream, cpal::BuildStreamError> {
     let host = cpal::default_host();
     let device = host.default_output_device().expect("failed to find output device");
     let config = device.default_output_config().unwrap();
 
     match config.sample_format() {
-        cpal::SampleFormat::F32 => create_stream(cur_weight, &device, &config.into()),
+        cpal::SampleFormat::F32 => create_stream(cur_weight, &device, &config.into(), params),
         sample_format => panic!("Unsupported sample format {sample_format}")
     }
 }
 
-// Maps a weight value to a frequency in the overtone series of A110
-fn weight_to_freq(weight: f32) -> f32 {
-    110.0 * (weight.trunc() + 1.0)
+// Maps a weight value to a continuous position in the A110 overtone series: the
+// integer part selects the overtone, the fractional part is how far we've glided
+// towards the next one.
+fn weight_to_overtone(weight: f32) -> f32 {
+    weight.max(0.0) + 1.0
+}
+
+// One sine partial that remembers its own phase so a frequency change doesn't cause an
+// audible click (the same fix the single-oscillator version used, just per-partial now).
+struct PartialOscillator {
+    sample_clock: f32,
+    phase: f32,
+    phase_offset: f32,
+    prev_freq: f32,
+}
+
+impl PartialOscillator {
+    fn new() -> Self {
+        Self {
+            sample_clock: 0.0,
+            phase: 0.0,
+            phase_offset: 0.0,
+            prev_freq: 0.0,
+        }
+    }
+
+    fn next(&mut self, freq: f32, sample_rate: f32) -> f32 {
+        self.sample_clock = (self.sample_clock + 1.0) % sample_rate;
+        if freq != self.prev_freq {
+            self.prev_freq = freq;
+            self.sample_clock = 1.0;
+            self.phase_offset = self.phase % (2.0 * std::f32::consts::PI);
+        }
+        self.phase = self.sample_clock * freq * 2.0 * std::f32::consts::PI / sample_rate
+            + self.phase_offset;
+        self.phase.sin()
+    }
 }
 
-fn create_stream(cur_weight: Arc<Mutex<f32>>, device: &cpal::Device, config: &cpal::StreamConfig)
-    -> Result<cpal::Stream, cpal::BuildStreamError>
-{
+fn create_stream(
+    cur_weight: Arc<Mutex<f32>>,
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    params: SynthParams,
+) -> Result<cpal::Stream, cpal::BuildStreamError> {
     let sample_rate = config.sample_rate.0 as f32;
     let channels = config.channels as usize;
-    let mut sample_clock = 0f32;
-    let mut phase = 0.0;
-    let mut phase_offset = 0.0;
-    let mut prev_freq = 0.0;
+    let partial_count = params.partial_count;
+    let amplitude_curve = params.amplitude_curve;
+    let amplitude_total: f32 = (1..=partial_count).map(amplitude_curve).sum();
+
+    // Two voices straddling the current overtone, crossfaded by the fractional weight
+    // so the perceived pitch glides smoothly instead of stepping at each whole kg.
+    let mut low_partials: Vec<_> = (0..partial_count).map(|_| PartialOscillator::new()).collect();
+    let mut high_partials: Vec<_> = (0..partial_count).map(|_| PartialOscillator::new()).collect();
+
     let mut next_value = move || {
-        sample_clock = (sample_clock + 1.0) % sample_rate;
-        let freq = weight_to_freq(*cur_weight.lock().unwrap());
-        if freq != prev_freq {
-            prev_freq = freq;
-            sample_clock = 1.0;
-            phase_offset = phase % (2.0 * std::f32::consts::PI);
+        let weight = *cur_weight.lock().unwrap();
+        let overtone = weight_to_overtone(weight);
+        let low = overtone.trunc();
+        let frac = overtone.fract();
+        let low_gain = 1.0 - frac;
+        let high_gain = frac;
+
+        let mut sample = 0.0;
+        for k in 1..=partial_count {
+            let amp = amplitude_curve(k) / amplitude_total;
+            let harmonic = k as f32;
+            sample += amp * low_gain * low_partials[k - 1].next(A_110 * low * harmonic, sample_rate);
+            sample += amp * high_gain * high_partials[k - 1].next(A_110 * (low + 1.0) * harmonic, sample_rate);
         }
-        phase = sample_clock * freq * 2.0 * std::f32::consts::PI / sample_rate
-            + phase_offset;
-        phase.sin()
+
+        let gain = (weight.abs() / MAX_WEIGHT_KG).min(1.0);
+        sample * gain
     };
 
     device.build_output_stream(
@@ -59,4 +133,3 @@ fn write_data(output: &mut [f32], channels: usize, next_sample: &mut dyn FnMut()
         }
     }
 }
-
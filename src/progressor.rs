@@ -1,16 +1,21 @@
 use std::sync::{Arc, Mutex};
 use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::time::Duration;
 
 use futures_lite::stream::StreamExt;
+use tokio::sync::mpsc;
 use uuid::{uuid, Uuid};
 use btleplug::{
-    platform::Manager,
+    platform::{Adapter, Manager, PeripheralId},
     api::{
         Manager as _,
         Central,
-        ScanFilter,
-        CentralEvent,
+        Characteristic,
         Peripheral,
+        ScanFilter,
         WriteType,
     }
 };
@@ -20,52 +25,273 @@ const CONTROL_UUID: Uuid = uuid!("7e4e1703-1ea6-40c9-9dcc-13d34ffead57");
 const DATA_UUID: Uuid = uuid!("7e4e1702-1ea6-40c9-9dcc-13d34ffead57");
 
 // opcodes
+const TARE_SCALE: u8 = 0x64;
 const START_WEIGHT_MEASUREMENT: u8 = 0x65;
 const END_WEIGHT_MEASUREMENT: u8 = 0x66;
+const GET_APP_VERSION: u8 = 0x6A;
+const GET_ERROR_INFORMATION: u8 = 0x6C;
+const CLEAR_ERROR_INFORMATION: u8 = 0x6D;
+const SHUTDOWN: u8 = 0x6E;
+const SAMPLE_BATTERY_VOLTAGE: u8 = 0x6F;
+
+// How long to listen for advertisements before picking a device.
+const SCAN_TIME: Duration = Duration::from_secs(5);
+
+/// The Tindeq Progressor control-characteristic command surface. Each command is
+/// written as `[opcode, payload_len, ...payload]`; none of these carry a payload.
+#[derive(Debug, Clone, Copy)]
+pub enum Command {
+    Tare,
+    StartWeightMeasurement,
+    StopWeightMeasurement,
+    SampleBatteryVoltage,
+    GetAppVersion,
+    GetErrorInformation,
+    ClearErrorInformation,
+    Shutdown,
+}
 
-#[derive(Debug)]
+impl Command {
+    fn opcode(self) -> u8 {
+        match self {
+            Command::Tare => TARE_SCALE,
+            Command::StartWeightMeasurement => START_WEIGHT_MEASUREMENT,
+            Command::StopWeightMeasurement => END_WEIGHT_MEASUREMENT,
+            Command::SampleBatteryVoltage => SAMPLE_BATTERY_VOLTAGE,
+            Command::GetAppVersion => GET_APP_VERSION,
+            Command::GetErrorInformation => GET_ERROR_INFORMATION,
+            Command::ClearErrorInformation => CLEAR_ERROR_INFORMATION,
+            Command::Shutdown => SHUTDOWN,
+        }
+    }
+
+    fn frame(self) -> [u8; 2] {
+        [self.opcode(), 0]
+    }
+}
+
+async fn send_command<P: Peripheral>(
+    device: &P,
+    control: &Characteristic,
+    command: Command,
+) -> Result<(), Box<dyn Error>> {
+    device.write(control, &command.frame(), WriteType::WithResponse).await?;
+    Ok(())
+}
+
+#[derive(Debug, PartialEq)]
 enum Response {
-    WeightMeasurement(f32, u32),
     SampleBatteryVoltage(u32),
+    /// One or more `(weight_kg, device_timestamp_us)` samples packed into a single
+    /// notification.
+    WeightMeasurement(Vec<(f32, u32)>),
+    AppVersion(String),
+    ErrorInformation(Vec<u8>),
     LowPowerWarning,
 }
 
 fn parse_response(i: Vec<u8>) -> Option<Response> {
-    let code = i.get(0)?;
+    let code = *i.get(0)?;
+    let len = *i.get(1)? as usize;
+    let payload = i.get(2..2 + len)?;
     match code {
-        0 => Some(Response::SampleBatteryVoltage(u32::from_le_bytes(i[2..6].try_into().ok()?))),
-        1 => Some(Response::WeightMeasurement(
-                    f32::from_le_bytes(i[2..6].try_into().ok()?),
-                    u32::from_le_bytes(i[6..10].try_into().ok()?)
-                    )
-                ),
+        0 => Some(Response::SampleBatteryVoltage(u32::from_le_bytes(payload.get(0..4)?.try_into().ok()?))),
+        1 => {
+            let samples = payload
+                .chunks_exact(8)
+                .map(|s| (
+                    f32::from_le_bytes(s[0..4].try_into().unwrap()),
+                    u32::from_le_bytes(s[4..8].try_into().unwrap()),
+                ))
+                .collect();
+            Some(Response::WeightMeasurement(samples))
+        }
+        2 => Some(Response::AppVersion(String::from_utf8(payload.to_vec()).ok()?)),
+        3 => Some(Response::ErrorInformation(payload.to_vec())),
         4 => Some(Response::LowPowerWarning),
         _ => None
     }
 }
 
-// Connect to progressor and record weight measurements
-pub async fn interaction(running: Arc<Mutex<bool>>, cur_weight: Arc<Mutex<f32>>)
-    -> Result<(), Box<dyn Error>> {
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_response_batches_multiple_weight_samples() {
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&1.5f32.to_le_bytes());
+        payload.extend_from_slice(&100u32.to_le_bytes());
+        payload.extend_from_slice(&1.75f32.to_le_bytes());
+        payload.extend_from_slice(&200u32.to_le_bytes());
+
+        let mut frame = vec![1, payload.len() as u8];
+        frame.extend_from_slice(&payload);
+
+        assert_eq!(
+            parse_response(frame),
+            Some(Response::WeightMeasurement(vec![(1.5, 100), (1.75, 200)])),
+        );
+    }
+
+    #[test]
+    fn parse_response_drops_trailing_partial_sample() {
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&1.5f32.to_le_bytes());
+        payload.extend_from_slice(&100u32.to_le_bytes());
+        payload.extend_from_slice(&[0xAA, 0xBB, 0xCC]); // short trailing chunk
+
+        let mut frame = vec![1, payload.len() as u8];
+        frame.extend_from_slice(&payload);
+
+        assert_eq!(
+            parse_response(frame),
+            Some(Response::WeightMeasurement(vec![(1.5, 100)])),
+        );
+    }
+
+    #[test]
+    fn parse_response_empty_weight_payload_yields_no_samples() {
+        let frame = vec![1, 0];
+
+        assert_eq!(parse_response(frame), Some(Response::WeightMeasurement(vec![])));
+    }
+}
+
+/// Parses a line typed at the interactive stdin prompt into the control command it
+/// requests. `quit` is handled by the caller since it ends the session rather than
+/// writing to the control characteristic.
+fn parse_user_command(line: &str) -> Option<Command> {
+    match line.trim() {
+        "tare" => Some(Command::Tare),
+        "battery" => Some(Command::SampleBatteryVoltage),
+        "version" => Some(Command::GetAppVersion),
+        "errors" => Some(Command::GetErrorInformation),
+        "clear-errors" => Some(Command::ClearErrorInformation),
+        "shutdown" => Some(Command::Shutdown),
+        _ => None,
+    }
+}
+
+/// A Progressor discovered during a [`scan`], with the advertisement data needed to
+/// choose between several of them when more than one is in range.
+#[derive(Debug, Clone)]
+pub struct ScanResult {
+    pub id: PeripheralId,
+    pub local_name: Option<String>,
+    pub rssi: Option<i16>,
+}
+
+/// Picks the Bluetooth adapter whose name contains `name` (case-insensitive), or the
+/// first available adapter if `name` is `None`. Lets users with more than one Bluetooth
+/// dongle choose which one to scan with instead of always using `adapters[0]`.
+pub async fn get_adapter_by_name(manager: &Manager, name: Option<&str>) -> Result<Adapter, Box<dyn Error>> {
+    let adapters = manager.adapters().await?;
+    match name {
+        None => adapters.into_iter().next().ok_or_else(|| "no adapters found".into()),
+        Some(name) => {
+            for adapter in adapters {
+                let info = adapter.adapter_info().await?;
+                if info.to_lowercase().contains(&name.to_lowercase()) {
+                    return Ok(adapter);
+                }
+            }
+            Err(format!("no adapter matching {name:?}").into())
+        }
+    }
+}
+
+/// Scans `central` for `scan_time`, collecting every discovered Progressor's advertised
+/// name and RSSI rather than returning as soon as the first one shows up. Results are
+/// sorted strongest signal first.
+async fn scan_with_adapter(central: &Adapter, scan_time: Duration) -> Result<Vec<ScanResult>, Box<dyn Error>> {
+    central.start_scan(ScanFilter { services: vec![SERVICE_UUID] }).await?;
+    tokio::time::sleep(scan_time).await;
+    central.stop_scan().await?;
+
+    let mut results = Vec::new();
+    for peripheral in central.peripherals().await? {
+        if let Some(props) = peripheral.properties().await? {
+            results.push(ScanResult {
+                id: peripheral.id(),
+                local_name: props.local_name,
+                rssi: props.rssi,
+            });
+        }
+    }
+
+    results.sort_by_key(|r| std::cmp::Reverse(r.rssi));
+    Ok(results)
+}
+
+/// Scans for Progressors for `scan_time` using the named adapter (or the first one
+/// found), returning every device seen instead of just the first. This is the
+/// scan-then-select flow callers use to pick a specific Progressor when several are
+/// in range, e.g. in a gym with multiple rigs set up.
+pub async fn scan(adapter_name: Option<String>, scan_time: Duration) -> Result<Vec<ScanResult>, Box<dyn Error>> {
     let manager = Manager::new().await?;
-    // Get the first bluetooth adapter
-    let adapters = manager.adapters().await.expect("unable to fetch adapters");
-    let central = adapters.get(0).expect("no adapters");
+    let central = get_adapter_by_name(&manager, adapter_name.as_deref()).await?;
+    scan_with_adapter(&central, scan_time).await
+}
 
-    central.start_scan(ScanFilter { services : vec![SERVICE_UUID] }).await?;
+/// Tuning knobs for the reconnection loop in [`interaction`]: how many consecutive
+/// dropouts to tolerate before giving up, and how long to wait before each retry.
+#[derive(Debug, Clone)]
+pub struct ReconnectConfig {
+    pub max_retries: u32,
+    pub backoff: Duration,
+}
 
-    let events = central.events().await?;
-    let device_id = events.filter_map
-        (|x| match x {
-            CentralEvent::DeviceDiscovered(id) => Some(id),
-            _ => None,
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        Self {
+            max_retries: 5,
+            backoff: Duration::from_secs(2),
         }
-        ).next().await.ok_or("device not found")?;
+    }
+}
+
+/// Opens `path` for a CSV recording of `(timestamp_us, weight_kg)` samples, writing the
+/// header only if the file is new. Appends across reconnects rather than truncating, so
+/// a retried session continues the same force-time curve.
+fn open_recording(path: &Path) -> std::io::Result<BufWriter<File>> {
+    let is_new = !path.exists();
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let mut writer = BufWriter::new(file);
+    if is_new {
+        writeln!(writer, "timestamp_us,weight_kg")?;
+    }
+    Ok(writer)
+}
+
+/// Why a measurement session ended.
+enum SessionEnd {
+    /// The user asked to stop; the caller should not retry.
+    UserStopped,
+    /// The notification stream ended because the link dropped; the caller may retry.
+    /// Notifications that fail to parse are ignored and do not end the session.
+    Disconnected,
+}
 
-    let device = central.peripheral(&device_id).await?;
+/// Runs the connect/subscribe/measure sequence against `device_id` once. Returns
+/// `Ok(SessionEnd::Disconnected)` (rather than an `Err`) when the notification stream
+/// simply ends, since that's the normal shape of a dropped BLE link.
+async fn run_session(
+    central: &Adapter,
+    device_id: &PeripheralId,
+    running: &Arc<Mutex<bool>>,
+    cur_weight: &Arc<Mutex<f32>>,
+    commands: &mut mpsc::Receiver<String>,
+    mut recorder: Option<&mut BufWriter<File>>,
+    retries: &mut u32,
+) -> Result<SessionEnd, Box<dyn Error>> {
+    let device = central.peripheral(device_id).await?;
 
     device.connect().await?;
     println!("connected");
+    // A dropout is only worth retrying when it's transient, so only count *consecutive*
+    // failures towards `max_retries`: once we're reconnected, the previous streak is over.
+    *retries = 0;
 
     device.discover_services().await?;
 
@@ -85,32 +311,125 @@ pub async fn interaction(running: Arc<Mutex<bool>>, cur_weight: Arc<Mutex<f32>>)
         .ok_or("data characteristic not found")?;
 
     device.subscribe(data_characteristic).await?;
-    device.write(
-        control_characteristic,
-        &[START_WEIGHT_MEASUREMENT, 0],
-        WriteType::WithResponse
-    ).await?;
+    send_command(&device, control_characteristic, Command::StartWeightMeasurement).await?;
 
     let mut notifications = device.notifications().await?;
+    // Once the stdin reader drops its sender (EOF, no tty, ...) recv() resolves to
+    // `None` on every poll rather than blocking; stop selecting on it or the loop below
+    // busy-spins instead of waiting on notifications like it's supposed to.
+    let mut commands_open = true;
 
-    while let Some(x) = notifications.next().await
-    {
-        if !(*running.lock().unwrap()) { break }
-        match parse_response(x.value) {
-            Some(Response::WeightMeasurement(w, _)) =>
-                *cur_weight.lock().unwrap() = w,
-            _ => (),
-        }
-    }
+    let end = loop {
+        if !(*running.lock().unwrap()) { break SessionEnd::UserStopped }
 
-    device.write(
-        control_characteristic,
-        &[END_WEIGHT_MEASUREMENT, 0],
-        WriteType::WithResponse
-    ).await?;
+        tokio::select! {
+            notification = notifications.next() => {
+                let Some(x) = notification else { break SessionEnd::Disconnected };
+                match parse_response(x.value) {
+                    Some(Response::WeightMeasurement(samples)) => {
+                        if let Some(&(w, _)) = samples.last() {
+                            *cur_weight.lock().unwrap() = w;
+                        }
+                        if let Some(writer) = recorder.as_deref_mut() {
+                            for (w, ts) in &samples {
+                                writeln!(writer, "{ts},{w}")?;
+                            }
+                            writer.flush()?;
+                        }
+                    }
+                    Some(Response::SampleBatteryVoltage(mv)) =>
+                        println!("battery: {mv} mV"),
+                    Some(Response::AppVersion(version)) =>
+                        println!("firmware version: {version}"),
+                    Some(Response::ErrorInformation(info)) =>
+                        println!("error info: {info:?}"),
+                    Some(Response::LowPowerWarning) =>
+                        println!("low power warning"),
+                    None => (),
+                }
+            }
+            line = commands.recv(), if commands_open => {
+                let Some(line) = line else { commands_open = false; continue };
+                match line.trim() {
+                    "quit" => *running.lock().unwrap() = false,
+                    other => match parse_user_command(other) {
+                        Some(command) => send_command(&device, control_characteristic, command).await?,
+                        None => eprintln!("unrecognized command: {other}"),
+                    }
+                }
+            }
+        }
+    };
 
-    device.disconnect().await?;
+    // Best-effort: the device may already be gone if we're here due to a dropout.
+    let _ = send_command(&device, control_characteristic, Command::StopWeightMeasurement).await;
+    let _ = device.disconnect().await;
     println!("disconnected");
 
-    Ok(())
+    Ok(end)
+}
+
+/// Connect to a progressor and record weight measurements.
+///
+/// `adapter_name` selects which Bluetooth adapter to scan with (see
+/// [`get_adapter_by_name`]); `device_name` narrows device selection to Progressors
+/// advertising that name. When several candidates remain, the strongest signal wins.
+/// If the BLE link drops mid-session (out of range, sleep, battery hiccup), the
+/// connect/subscribe/measure sequence is retried per `reconnect` instead of ending the
+/// whole session.
+///
+/// `commands` receives raw lines typed at an interactive prompt for the lifetime of the
+/// session, surviving reconnects. `tare`, `battery`, `version`, `errors`, `clear-errors`
+/// and `shutdown` are forwarded to the control characteristic, `quit` ends the session,
+/// and any other line is reported as unrecognized.
+///
+/// When `record_path` is given, every `(timestamp_us, weight_kg)` sample is appended to
+/// it as CSV, giving an accurate force-time curve for later analysis rather than just
+/// the most recent weight.
+pub async fn interaction(
+    running: Arc<Mutex<bool>>,
+    cur_weight: Arc<Mutex<f32>>,
+    adapter_name: Option<String>,
+    device_name: Option<String>,
+    reconnect: ReconnectConfig,
+    mut commands: mpsc::Receiver<String>,
+    record_path: Option<&Path>,
+) -> Result<(), Box<dyn Error>> {
+    let manager = Manager::new().await?;
+    let central = get_adapter_by_name(&manager, adapter_name.as_deref()).await?;
+
+    let mut candidates = scan_with_adapter(&central, SCAN_TIME).await?;
+    if let Some(name) = &device_name {
+        candidates.retain(|c| c.local_name.as_deref() == Some(name.as_str()));
+    }
+    let chosen = candidates.into_iter().next().ok_or("device not found")?;
+    let device_id = chosen.id;
+
+    let mut recorder = record_path.map(open_recording).transpose()?;
+
+    let mut retries = 0;
+    loop {
+        let result = run_session(
+            &central,
+            &device_id,
+            &running,
+            &cur_weight,
+            &mut commands,
+            recorder.as_mut(),
+            &mut retries,
+        ).await;
+        match result {
+            Ok(SessionEnd::UserStopped) => return Ok(()),
+            Ok(SessionEnd::Disconnected) | Err(_) if retries < reconnect.max_retries => {
+                retries += 1;
+                eprintln!(
+                    "progressor disconnected, reconnecting (attempt {retries}/{})...",
+                    reconnect.max_retries
+                );
+                tokio::time::sleep(reconnect.backoff).await;
+            }
+            Ok(SessionEnd::Disconnected) => return Err("progressor disconnected, retries exhausted".into()),
+            Err(e) => return Err(e),
+        }
+    }
 }